@@ -0,0 +1,377 @@
+//! Runs the swarm on its own spawned task and exposes it as a pair of tokio
+//! mpsc channels, so the gossip engine can be embedded in a GUI or server
+//! instead of only a terminal REPL like `main.rs`'s.
+
+use crate::{build_swarm, peer_score_thresholds, MyBehaviour, MyBehaviourEvent};
+use futures::stream::StreamExt;
+use libp2p::{
+    dcutr, gossipsub, identify, mdns, multiaddr::Protocol, relay, swarm::SwarmEvent, Multiaddr,
+    PeerId, Swarm,
+};
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// How often the backend polls connected peers' gossipsub scores to detect threshold crossings.
+const SCORE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Environment variable holding the relay server's multiaddr, e.g.
+/// `/ip4/1.2.3.4/tcp/4001/p2p/<relay-peer-id>`. When set, the backend dials the
+/// relay and reserves a circuit on it so that peers behind other NATs can
+/// reach it, falling back to the relayed connection until DCUtR hole-punches
+/// a direct one.
+const RELAY_ADDR_ENV: &str = "CHAT_RELAY_ADDR";
+
+/// Size of the command and event channels. Small, since the driver task
+/// drains commands and the caller is expected to drain events promptly.
+const CHANNEL_CAPACITY: usize = 32;
+
+/// Commands accepted on the backend's command channel.
+#[derive(Debug)]
+pub enum Command {
+    /// Publish `data` to `topic`.
+    Publish {
+        topic: gossipsub::IdentTopic,
+        data: Vec<u8>,
+    },
+    /// Subscribe to a topic.
+    Subscribe(gossipsub::IdentTopic),
+    /// Unsubscribe from a topic.
+    Unsubscribe(gossipsub::IdentTopic),
+    /// Dial an arbitrary address (a peer, or a relay).
+    Dial(Multiaddr),
+}
+
+/// Events emitted on the backend's event channel.
+#[derive(Debug)]
+pub enum Event {
+    /// A gossipsub message was received on a subscribed topic.
+    MessageReceived {
+        topic: gossipsub::TopicHash,
+        id: gossipsub::MessageId,
+        source: PeerId,
+        data: Vec<u8>,
+    },
+    /// mDNS discovered a peer on the local network.
+    PeerDiscovered(PeerId),
+    /// A previously discovered mDNS peer's record expired.
+    PeerExpired(PeerId),
+    /// A peer subscribed to a topic we are also subscribed to.
+    Subscribed {
+        peer_id: PeerId,
+        topic: gossipsub::TopicHash,
+    },
+}
+
+/// Owns the `Swarm<MyBehaviour>` on a spawned task; commands are sent in and
+/// events come out, decoupling the gossip engine from whatever is driving it.
+pub struct Backend {
+    commands: mpsc::Sender<Command>,
+    events: mpsc::Receiver<Event>,
+}
+
+impl Backend {
+    /// Builds the swarm, starts listening on TCP and (outside private-network
+    /// mode) QUIC, optionally dials a configured relay, and spawns the driver task.
+    pub fn spawn() -> Result<Self, Box<dyn Error>> {
+        // Resolved once and threaded through: both the transport choice in `build_swarm`
+        // and the QUIC-listen/relay-dial gating below must agree on the same decision,
+        // so re-deriving it from the env var/file independently at each call site is
+        // exactly the class of bug that caused `TransportError::MultiaddrNotSupported`.
+        let psk = crate::load_pnet_psk()?;
+        let is_pnet = psk.is_some();
+
+        let mut swarm = build_swarm(psk)?;
+
+        swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+
+        // QUIC has no PSK check of its own, so `build_swarm` never registers it in
+        // private-network (pnet) mode; listening on a QUIC multiaddr there would fail with
+        // `TransportError::MultiaddrNotSupported` since nothing understands it.
+        if !is_pnet {
+            swarm.listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse()?)?;
+        }
+
+        let relay = match std::env::var(RELAY_ADDR_ENV) {
+            Ok(relay_addr) => {
+                // The relay client transport is disabled outright in private-network (pnet)
+                // mode (see `build_swarm`), so a configured relay address could never be
+                // reached there.
+                if is_pnet {
+                    return Err(format!(
+                        "{RELAY_ADDR_ENV} is not supported together with a pnet pre-shared key: \
+                         the relay client is disabled in private-network mode"
+                    )
+                    .into());
+                }
+                let relay_addr: Multiaddr = relay_addr.parse()?;
+                let Some(peer_id) = relay_peer_id(&relay_addr) else {
+                    return Err(format!(
+                        "{RELAY_ADDR_ENV} must include a trailing /p2p/<peer-id> component"
+                    )
+                    .into());
+                };
+                swarm.dial(relay_addr.clone())?;
+                Some(RelayState::new(peer_id, relay_addr))
+            }
+            Err(_) => None,
+        };
+
+        let (command_tx, command_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let (event_tx, event_rx) = mpsc::channel(CHANNEL_CAPACITY);
+
+        tokio::spawn(run(swarm, relay, command_rx, event_tx));
+
+        Ok(Self {
+            commands: command_tx,
+            events: event_rx,
+        })
+    }
+
+    /// Sends a command to the backend. Fails only if the driver task has stopped.
+    pub async fn command(&self, command: Command) -> Result<(), mpsc::error::SendError<Command>> {
+        self.commands.send(command).await
+    }
+
+    /// Awaits the next event emitted by the backend. Returns `None` once the
+    /// driver task has stopped and no more events will ever arrive.
+    pub async fn next_event(&mut self) -> Option<Event> {
+        self.events.recv().await
+    }
+}
+
+/// Extracts the trailing `/p2p/<peer-id>` component of a relay multiaddr,
+/// e.g. `/ip4/1.2.3.4/tcp/4001/p2p/<peer-id>` -> `<peer-id>`. We need it to
+/// know which peer's identify info is allowed to authorize our circuit
+/// reservation and external address.
+fn relay_peer_id(addr: &Multiaddr) -> Option<PeerId> {
+    match addr.iter().last() {
+        Some(Protocol::P2p(peer_id)) => Some(peer_id),
+        _ => None,
+    }
+}
+
+/// Tracks progress of the relay reservation handshake: the circuit-relay-v2
+/// client needs an established, identified connection to the relay before
+/// `listen_on`-ing the `/p2p-circuit` address will succeed, so we wait for
+/// identify to complete in both directions first, mirroring the wait loop in
+/// the upstream `relay-client`/`dcutr` examples.
+struct RelayState {
+    peer_id: PeerId,
+    addr: Multiaddr,
+    told_relay_observed_addr: bool,
+    learned_observed_addr: bool,
+    reservation_requested: bool,
+}
+
+impl RelayState {
+    fn new(peer_id: PeerId, addr: Multiaddr) -> Self {
+        Self {
+            peer_id,
+            addr,
+            told_relay_observed_addr: false,
+            learned_observed_addr: false,
+            reservation_requested: false,
+        }
+    }
+
+    /// Once both halves of the identify exchange with the relay have
+    /// completed, requests the circuit reservation exactly once.
+    fn maybe_request_reservation(&mut self, swarm: &mut Swarm<MyBehaviour>) {
+        if self.reservation_requested
+            || !self.told_relay_observed_addr
+            || !self.learned_observed_addr
+        {
+            return;
+        }
+        self.reservation_requested = true;
+        if let Err(e) = swarm.listen_on(self.addr.clone().with(Protocol::P2pCircuit)) {
+            tracing::info!("Relay circuit reservation error: {e:?}");
+        }
+    }
+}
+
+/// Drives the swarm: selects over incoming commands and `swarm.select_next_some()`,
+/// translating each into the corresponding gossipsub call or outgoing [`Event`].
+async fn run(
+    mut swarm: Swarm<MyBehaviour>,
+    mut relay: Option<RelayState>,
+    mut commands: mpsc::Receiver<Command>,
+    events: mpsc::Sender<Event>,
+) {
+    let thresholds = peer_score_thresholds();
+    let mut last_logged_scores: HashMap<PeerId, f64> = HashMap::new();
+    let mut score_check_interval = tokio::time::interval(SCORE_CHECK_INTERVAL);
+
+    loop {
+        tokio::select! {
+            command = commands.recv() => {
+                let Some(command) = command else {
+                    // no senders left: nothing more will ever arrive, so shut down
+                    return;
+                };
+                match command {
+                    Command::Publish { topic, data } => {
+                        if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic, data) {
+                            tracing::info!("Publish error: {e:?}");
+                        }
+                    }
+                    Command::Subscribe(topic) => {
+                        if let Err(e) = swarm.behaviour_mut().gossipsub.subscribe(&topic) {
+                            tracing::info!("Subscribe error: {e:?}");
+                        }
+                    }
+                    Command::Unsubscribe(topic) => {
+                        swarm.behaviour_mut().gossipsub.unsubscribe(&topic);
+                    }
+                    Command::Dial(addr) => {
+                        if let Err(e) = swarm.dial(addr) {
+                            tracing::info!("Dial error: {e:?}");
+                        }
+                    }
+                }
+            }
+            _ = score_check_interval.tick() => {
+                check_peer_scores(&swarm, &thresholds, &mut last_logged_scores);
+            }
+            event = swarm.select_next_some() => {
+                if !handle_swarm_event(&mut swarm, event, &events, &mut relay).await {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Translates one `SwarmEvent` into zero or more outgoing [`Event`]s, applying
+/// whatever swarm-side bookkeeping (adding explicit peers, external addresses,
+/// ...) the original inline event loop used to perform. Returns `false` if the
+/// event receiver has been dropped, signalling the driver task should stop.
+async fn handle_swarm_event(
+    swarm: &mut Swarm<MyBehaviour>,
+    event: SwarmEvent<MyBehaviourEvent>,
+    events: &mpsc::Sender<Event>,
+    relay: &mut Option<RelayState>,
+) -> bool {
+    match event {
+        SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
+            for (peer_id, _multiaddr) in list {
+                swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                if events.send(Event::PeerDiscovered(peer_id)).await.is_err() {
+                    return false;
+                }
+            }
+        }
+        SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Expired(list))) => {
+            for (peer_id, _multiaddr) in list {
+                swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                if events.send(Event::PeerExpired(peer_id)).await.is_err() {
+                    return false;
+                }
+            }
+        }
+        SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message {
+            propagation_source,
+            message_id,
+            message,
+        })) => {
+            if events
+                .send(Event::MessageReceived {
+                    topic: message.topic,
+                    id: message_id,
+                    source: propagation_source,
+                    data: message.data,
+                })
+                .await
+                .is_err()
+            {
+                return false;
+            }
+        }
+        SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed {
+            peer_id,
+            topic,
+        })) => {
+            if events
+                .send(Event::Subscribed { peer_id, topic })
+                .await
+                .is_err()
+            {
+                return false;
+            }
+        }
+        SwarmEvent::NewListenAddr { address, .. } => {
+            tracing::info!("Local node is listening on {address}");
+        }
+        SwarmEvent::Behaviour(MyBehaviourEvent::Identify(identify::Event::Received {
+            peer_id,
+            info,
+            ..
+        })) => {
+            tracing::info!("Identified {peer_id} as observing us at {}", info.observed_addr);
+            // Only the configured relay's view of our address is trusted as an external
+            // address: any LAN peer discovered via mDNS could otherwise hand us a bogus
+            // observed address that DCUtR would then rely on.
+            if let Some(relay) = relay {
+                if peer_id == relay.peer_id {
+                    swarm.add_external_address(info.observed_addr);
+                    relay.learned_observed_addr = true;
+                    relay.maybe_request_reservation(swarm);
+                }
+            }
+        }
+        SwarmEvent::Behaviour(MyBehaviourEvent::Identify(identify::Event::Sent {
+            peer_id,
+            ..
+        })) => {
+            if let Some(relay) = relay {
+                if peer_id == relay.peer_id {
+                    relay.told_relay_observed_addr = true;
+                    relay.maybe_request_reservation(swarm);
+                }
+            }
+        }
+        SwarmEvent::Behaviour(MyBehaviourEvent::RelayClient(Some(
+            relay::client::Event::ReservationReqAccepted { relay_peer_id, .. },
+        ))) => {
+            tracing::info!("Relay {relay_peer_id} accepted our circuit reservation");
+        }
+        SwarmEvent::Behaviour(MyBehaviourEvent::Dcutr(dcutr::Event {
+            remote_peer_id,
+            result,
+        })) => match result {
+            Ok(_) => tracing::info!(
+                "Hole punch to {remote_peer_id} succeeded, connection is now direct"
+            ),
+            Err(e) => tracing::info!("Hole punch to {remote_peer_id} failed: {e:?}"),
+        },
+        _ => {}
+    }
+    true
+}
+
+/// Logs a peer's gossipsub score crossing one of the configured thresholds,
+/// tracking each peer's score from the previous check so we only log on the crossing
+/// itself rather than on every tick.
+fn check_peer_scores(
+    swarm: &Swarm<MyBehaviour>,
+    thresholds: &gossipsub::PeerScoreThresholds,
+    last_logged_scores: &mut HashMap<PeerId, f64>,
+) {
+    let peer_ids: Vec<PeerId> = swarm.connected_peers().copied().collect();
+    for peer_id in peer_ids {
+        let Some(score) = swarm.behaviour().gossipsub.peer_score(&peer_id) else {
+            continue;
+        };
+        let previous = last_logged_scores.insert(peer_id, score);
+        let crossed_below = |threshold: f64| score < threshold && previous.map_or(true, |p| p >= threshold);
+        if crossed_below(thresholds.graylist_threshold) {
+            tracing::info!("Peer {peer_id} dropped below graylist threshold (score: {score}), now fully ignored");
+        } else if crossed_below(thresholds.publish_threshold) {
+            tracing::info!("Peer {peer_id} dropped below publish threshold (score: {score}), our messages won't be forwarded to it");
+        } else if crossed_below(thresholds.gossip_threshold) {
+            tracing::info!("Peer {peer_id} dropped below gossip threshold (score: {score}), excluded from the mesh");
+        }
+    }
+}
@@ -0,0 +1,206 @@
+//! Persistent, queryable cache of received gossipsub messages, backed by
+//! SQLite. Messages are keyed by topic and the content-addressed `MessageId`
+//! produced by `message_id_fn` in [`crate::build_swarm`], so a node that
+//! restarts can replay the messages it received earlier and skip ones it has
+//! already seen. Only messages this node received are stored; nothing is
+//! fetched from other peers. Payloads that are valid JSON can additionally be filtered with
+//! JSONPath expressions (e.g. `$.type`).
+
+use libp2p::gossipsub::{MessageId, TopicHash};
+use serde_json::Value;
+use sqlx::sqlite::{SqlitePoolOptions, SqliteRow};
+use sqlx::{Row, SqlitePool};
+use std::error::Error;
+
+/// One message as read back out of the cache.
+#[derive(Debug, Clone)]
+pub struct CachedMessage {
+    pub topic: String,
+    pub message_id: String,
+    pub data: Vec<u8>,
+    pub received_at: i64,
+}
+
+fn row_to_message(row: SqliteRow) -> CachedMessage {
+    CachedMessage {
+        topic: row.get("topic"),
+        message_id: row.get("message_id"),
+        data: row.get("data"),
+        received_at: row.get("received_at"),
+    }
+}
+
+/// A durable, queryable store of every gossipsub message this node has received.
+pub struct MessageCache {
+    pool: SqlitePool,
+}
+
+impl MessageCache {
+    /// Opens (creating if needed) the SQLite database at `path` and ensures the cache's schema exists.
+    pub async fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        let pool = SqlitePoolOptions::new()
+            .connect(&format!("sqlite://{path}?mode=rwc"))
+            .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS messages (
+                topic TEXT NOT NULL,
+                message_id TEXT NOT NULL,
+                data BLOB NOT NULL,
+                received_at INTEGER NOT NULL,
+                PRIMARY KEY (topic, message_id)
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        Ok(Self { pool })
+    }
+
+    /// Stores `data` for `message_id` on `topic`, deduplicating against
+    /// already-seen ids. Returns `true` if the message was newly stored,
+    /// `false` if it was already cached.
+    pub async fn store(
+        &self,
+        topic: &TopicHash,
+        message_id: &MessageId,
+        data: &[u8],
+        received_at: i64,
+    ) -> Result<bool, Box<dyn Error>> {
+        let result = sqlx::query(
+            "INSERT INTO messages (topic, message_id, data, received_at)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (topic, message_id) DO NOTHING",
+        )
+        .bind(topic.to_string())
+        .bind(message_id.to_string())
+        .bind(data)
+        .bind(received_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Returns every message stored for `topic` since `timestamp` (inclusive), oldest first.
+    pub async fn get_since(
+        &self,
+        topic: &TopicHash,
+        timestamp: i64,
+    ) -> Result<Vec<CachedMessage>, Box<dyn Error>> {
+        let rows = sqlx::query(
+            "SELECT topic, message_id, data, received_at FROM messages
+             WHERE topic = ?1 AND received_at >= ?2
+             ORDER BY received_at ASC",
+        )
+        .bind(topic.to_string())
+        .bind(timestamp)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(row_to_message).collect())
+    }
+
+    /// Returns every message stored for `topic` whose JSON payload has a match
+    /// for `jsonpath` (e.g. `$.type`). Messages whose payload is not valid
+    /// JSON are skipped rather than treated as an error.
+    pub async fn query(
+        &self,
+        topic: &TopicHash,
+        jsonpath: &str,
+    ) -> Result<Vec<CachedMessage>, Box<dyn Error>> {
+        let path = jsonpath_rust::JsonPath::try_from(jsonpath)?;
+
+        let rows = sqlx::query(
+            "SELECT topic, message_id, data, received_at FROM messages
+             WHERE topic = ?1
+             ORDER BY received_at ASC",
+        )
+        .bind(topic.to_string())
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut matches = Vec::new();
+        for row in rows {
+            let message = row_to_message(row);
+            let Ok(value) = serde_json::from_slice::<Value>(&message.data) else {
+                continue;
+            };
+            if !path.find(&value).is_empty() {
+                matches.push(message);
+            }
+        }
+        Ok(matches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::gossipsub::IdentTopic;
+
+    /// Opens a cache backed by a fresh temp file, named after the calling test so
+    /// concurrently-run tests don't share a database.
+    async fn open_temp_cache(name: &str) -> MessageCache {
+        let path = std::env::temp_dir().join(format!("chat_example_cache_test_{name}.sqlite3"));
+        let _ = std::fs::remove_file(&path);
+        MessageCache::open(path.to_str().unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn store_dedupes_on_topic_and_message_id() {
+        let cache = open_temp_cache("dedupes").await;
+        let topic = IdentTopic::new("t").hash();
+        let id = MessageId::from(b"m1".to_vec());
+
+        assert!(cache.store(&topic, &id, b"first", 0).await.unwrap());
+        assert!(!cache.store(&topic, &id, b"second", 1).await.unwrap());
+
+        let stored = cache.get_since(&topic, 0).await.unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].data, b"first");
+    }
+
+    #[tokio::test]
+    async fn get_since_respects_the_timestamp_bound() {
+        let cache = open_temp_cache("get_since").await;
+        let topic = IdentTopic::new("t").hash();
+
+        cache
+            .store(&topic, &MessageId::from(b"old".to_vec()), b"old", 10)
+            .await
+            .unwrap();
+        cache
+            .store(&topic, &MessageId::from(b"new".to_vec()), b"new", 20)
+            .await
+            .unwrap();
+
+        let recent = cache.get_since(&topic, 15).await.unwrap();
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].message_id, MessageId::from(b"new".to_vec()).to_string());
+    }
+
+    #[tokio::test]
+    async fn query_filters_by_jsonpath_and_skips_non_json() {
+        let cache = open_temp_cache("query").await;
+        let topic = IdentTopic::new("t").hash();
+
+        cache
+            .store(
+                &topic,
+                &MessageId::from(b"json".to_vec()),
+                br#"{"type":"greeting"}"#,
+                0,
+            )
+            .await
+            .unwrap();
+        cache
+            .store(&topic, &MessageId::from(b"not-json".to_vec()), b"not json", 0)
+            .await
+            .unwrap();
+
+        let matches = cache.query(&topic, "$.type").await.unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].message_id, MessageId::from(b"json".to_vec()).to_string());
+    }
+}
@@ -1,10 +1,220 @@
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::{upgrade::Version, Boxed};
+use libp2p::pnet::{PnetConfig, PreSharedKey};
 use libp2p::Swarm;
-use libp2p::{gossipsub, mdns, swarm::NetworkBehaviour, tcp, tls, yamux};
+use libp2p::{
+    dcutr, gossipsub, identify, identity, mdns, relay, swarm::NetworkBehaviour, tcp, tls, yamux,
+    PeerId,
+};
+use libp2p::Transport;
 use std::hash::{Hash, Hasher};
 use std::time::Duration;
 use std::{collections::hash_map::DefaultHasher, error::Error};
 use tokio::io;
 
+pub mod backend;
+pub mod cache;
+
+/// Name of the gossipsub topic the chat publishes and subscribes to. Shared
+/// with `main.rs` so the peer scoring params below and the topic peers
+/// actually gossip on always agree.
+pub const CHAT_TOPIC: &str = "test-topic";
+
+/// Name of the environment variable holding the pre-shared key directly, as a
+/// hex-encoded fingerprint (see [`PreSharedKey`]'s `FromStr` impl for the
+/// expected format).
+const PNET_KEY_ENV: &str = "CHAT_PNET_PSK";
+
+/// Name of the environment variable pointing to a file containing the
+/// pre-shared key, using the same `ipfs swarm.key` fingerprint format.
+const PNET_KEY_PATH_ENV: &str = "CHAT_PNET_KEY_PATH";
+
+/// Loads the private network pre-shared key, if one was configured, from
+/// either `CHAT_PNET_PSK` or the file pointed to by `CHAT_PNET_KEY_PATH`.
+/// When neither is set, the node runs on the public libp2p network as usual.
+///
+/// Returns `Err` when one of the two env vars *is* set but the key fails to
+/// parse, or the key file can't be read: silently falling back to `None` in
+/// that case would mean a typo'd path or a truncated key file quietly joins
+/// the public, unguarded network instead of refusing to start.
+pub(crate) fn load_pnet_psk() -> Result<Option<PreSharedKey>, Box<dyn Error>> {
+    if let Ok(key) = std::env::var(PNET_KEY_ENV) {
+        return Ok(Some(key.parse().map_err(|_| {
+            format!("{PNET_KEY_ENV} is set but is not a valid pre-shared key")
+        })?));
+    }
+    let Ok(path) = std::env::var(PNET_KEY_PATH_ENV) else {
+        return Ok(None);
+    };
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|e| format!("failed to read {PNET_KEY_PATH_ENV} ({path}): {e}"))?;
+    let psk = contents
+        .parse()
+        .map_err(|_| format!("{path} does not contain a valid pre-shared key"))?;
+    Ok(Some(psk))
+}
+
+/// Builds the TCP transport, optionally wrapping it with [`PnetConfig`] so
+/// that only peers holding the matching pre-shared key can complete the
+/// handshake. This must happen before the TLS/Yamux upgrade, since pnet
+/// operates on the raw encrypted-with-a-shared-secret byte stream.
+fn build_tcp_transport(
+    key: &identity::Keypair,
+    psk: Option<PreSharedKey>,
+) -> io::Result<Boxed<(PeerId, StreamMuxerBox)>> {
+    let tcp = tcp::tokio::Transport::new(tcp::Config::default());
+    let tcp = match psk {
+        Some(psk) => tcp
+            .and_then(move |socket, _| PnetConfig::new(psk).handshake(socket))
+            .boxed(),
+        None => tcp.boxed(),
+    };
+
+    Ok(tcp
+        .upgrade(Version::V1Lazy)
+        .authenticate(tls::Config::new(key).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?)
+        .multiplex(yamux::Config::default())
+        .timeout(Duration::from_secs(20))
+        .boxed())
+}
+
+/// Weights and decay settings for gossipsub's peer scoring. See
+/// https://docs.libp2p.io/concepts/pubsub/overview/#peer-scoring for what each
+/// component measures; the defaults here mirror the ones suggested in the
+/// gossipsub spec for a single-topic mesh.
+#[derive(Debug, Clone)]
+pub struct PeerScoreConfig {
+    /// Weight applied to the topic's score when folded into the overall peer score.
+    pub topic_weight: f64,
+    /// Weight for time spent in the mesh, rewarding peers that stick around.
+    pub time_in_mesh_weight: f64,
+    /// Cap on the time-in-mesh component so long-lived peers stop accruing further reward.
+    pub time_in_mesh_cap: f64,
+    /// How long a peer has to stay in the mesh to earn one unit of time-in-mesh score.
+    pub time_in_mesh_quantum: Duration,
+    /// Reward weight for being one of the first to deliver a valid message.
+    pub first_message_deliveries_weight: f64,
+    /// Cap on the first-message-deliveries counter.
+    pub first_message_deliveries_cap: f64,
+    /// Per-decay-interval decay factor applied to the first-message-deliveries counter.
+    pub first_message_deliveries_decay: f64,
+    /// Penalty weight for delivering fewer mesh messages than expected.
+    pub mesh_message_deliveries_weight: f64,
+    /// Cap on the mesh-message-deliveries counter.
+    pub mesh_message_deliveries_cap: f64,
+    /// Expected mesh-message-deliveries rate; falling short of this is penalized.
+    pub mesh_message_deliveries_threshold: f64,
+    /// Per-decay-interval decay factor applied to the mesh-message-deliveries counter.
+    pub mesh_message_deliveries_decay: f64,
+    /// Grace period after joining the mesh before the delivery-rate penalty kicks in.
+    pub mesh_message_deliveries_activation: Duration,
+    /// Penalty weight for messages from this peer that failed validation.
+    pub invalid_message_deliveries_weight: f64,
+    /// Per-decay-interval decay factor applied to the invalid-message-deliveries counter.
+    pub invalid_message_deliveries_decay: f64,
+    /// Penalty weight applied per peer sharing an IP above `ip_colocation_factor_threshold`.
+    pub ip_colocation_factor_weight: f64,
+    /// Number of peers allowed to share an IP before the colocation penalty applies.
+    pub ip_colocation_factor_threshold: f64,
+    /// How often all the above counters decay towards zero.
+    pub decay_interval: Duration,
+    /// Below this score, a peer is excluded from the mesh for that topic.
+    pub gossip_threshold: f64,
+    /// Below this score, our own messages are not forwarded to the peer.
+    pub publish_threshold: f64,
+    /// Below this score, the peer is ignored entirely (graylisted).
+    pub graylist_threshold: f64,
+    /// Minimum score required to accept peer exchange info from a peer during pruning.
+    pub accept_px_threshold: f64,
+    /// Score above which a peer is eligible for opportunistic grafting.
+    pub opportunistic_graft_threshold: f64,
+}
+
+impl Default for PeerScoreConfig {
+    fn default() -> Self {
+        Self {
+            topic_weight: 1.0,
+            time_in_mesh_weight: 0.01,
+            time_in_mesh_cap: 3600.0,
+            time_in_mesh_quantum: Duration::from_secs(1),
+            first_message_deliveries_weight: 1.0,
+            first_message_deliveries_cap: 2000.0,
+            first_message_deliveries_decay: 0.5,
+            mesh_message_deliveries_weight: -1.0,
+            mesh_message_deliveries_cap: 100.0,
+            mesh_message_deliveries_threshold: 20.0,
+            mesh_message_deliveries_decay: 0.5,
+            mesh_message_deliveries_activation: Duration::from_secs(30),
+            invalid_message_deliveries_weight: -2.0,
+            invalid_message_deliveries_decay: 0.3,
+            ip_colocation_factor_weight: -5.0,
+            ip_colocation_factor_threshold: 3.0,
+            decay_interval: Duration::from_secs(10),
+            gossip_threshold: -10.0,
+            publish_threshold: -50.0,
+            graylist_threshold: -80.0,
+            accept_px_threshold: 10.0,
+            opportunistic_graft_threshold: 5.0,
+        }
+    }
+}
+
+/// Builds the `PeerScoreParams`/`PeerScoreThresholds` pair gossipsub needs for
+/// `with_peer_score`, scoring [`CHAT_TOPIC`] according to `config`.
+fn build_peer_score(
+    config: &PeerScoreConfig,
+) -> (gossipsub::PeerScoreParams, gossipsub::PeerScoreThresholds) {
+    let topic_params = gossipsub::TopicScoreParams {
+        topic_weight: config.topic_weight,
+        time_in_mesh_weight: config.time_in_mesh_weight,
+        time_in_mesh_cap: config.time_in_mesh_cap,
+        time_in_mesh_quantum: config.time_in_mesh_quantum,
+        first_message_deliveries_weight: config.first_message_deliveries_weight,
+        first_message_deliveries_cap: config.first_message_deliveries_cap,
+        first_message_deliveries_decay: config.first_message_deliveries_decay,
+        mesh_message_deliveries_weight: config.mesh_message_deliveries_weight,
+        mesh_message_deliveries_cap: config.mesh_message_deliveries_cap,
+        mesh_message_deliveries_threshold: config.mesh_message_deliveries_threshold,
+        mesh_message_deliveries_decay: config.mesh_message_deliveries_decay,
+        mesh_message_deliveries_activation: config.mesh_message_deliveries_activation,
+        mesh_failure_penalty_weight: config.mesh_message_deliveries_weight,
+        mesh_failure_penalty_decay: config.mesh_message_deliveries_decay,
+        invalid_message_deliveries_weight: config.invalid_message_deliveries_weight,
+        invalid_message_deliveries_decay: config.invalid_message_deliveries_decay,
+        ..Default::default()
+    };
+
+    let mut topics = std::collections::HashMap::new();
+    topics.insert(
+        gossipsub::IdentTopic::new(CHAT_TOPIC).hash(),
+        topic_params,
+    );
+
+    let params = gossipsub::PeerScoreParams {
+        topics,
+        ip_colocation_factor_weight: config.ip_colocation_factor_weight,
+        ip_colocation_factor_threshold: config.ip_colocation_factor_threshold,
+        decay_interval: config.decay_interval,
+        ..Default::default()
+    };
+
+    let thresholds = gossipsub::PeerScoreThresholds {
+        gossip_threshold: config.gossip_threshold,
+        publish_threshold: config.publish_threshold,
+        graylist_threshold: config.graylist_threshold,
+        accept_px_threshold: config.accept_px_threshold,
+        opportunistic_graft_threshold: config.opportunistic_graft_threshold,
+    };
+
+    (params, thresholds)
+}
+
+/// Returns the peer score thresholds the chat runs with, so the backend can log
+/// when a peer's score crosses one of them without duplicating the numbers.
+pub fn peer_score_thresholds() -> gossipsub::PeerScoreThresholds {
+    build_peer_score(&PeerScoreConfig::default()).1
+}
+
 /// The `NetworkBehaviour` trait specifies the behaviour of the nodes
 /// in the peer-to-peer network in all the situation/events that might
 /// occur. Take a look at the source code such trait for more.
@@ -19,75 +229,132 @@ use tokio::io;
 /// to publish and receive DNS records within a local network. In libp2p, mDNS
 /// is used for peer discovery, allowing peers to find each other on the same
 /// local network (e.g., your wi-fi) without any configuration
+/// * Relay client: lets the node reserve a circuit on a relay server so peers
+/// behind a different NAT can reach it even before a direct connection exists.
+/// `None` when running on a private (pnet) network, since the relay client's
+/// transport has no PSK check and would otherwise let outsiders join through it
+/// * Identify: exchanges protocol/address info with connected peers, which is
+/// also how we learn our own observed (externally visible) address
+/// * DCUtR (Direct Connection Upgrade through Relay): attempts to upgrade a
+/// relayed connection into a direct one via hole punching
 ///
 /// References:
 /// * publish-subscribe, gossip: https://docs.libp2p.io/concepts/pubsub/overview/
 /// * mDNS: https://docs.libp2p.io/concepts/discovery-routing/mdns/
+/// * circuit relay: https://docs.libp2p.io/concepts/nat/circuit-relay/
+/// * hole punching / DCUtR: https://docs.libp2p.io/concepts/nat/hole-punching/
 #[derive(NetworkBehaviour)]
 pub struct MyBehaviour {
     pub gossipsub: gossipsub::Behaviour,
     pub mdns: mdns::tokio::Behaviour,
+    pub relay_client: Option<relay::client::Behaviour>,
+    pub identify: identify::Behaviour,
+    pub dcutr: dcutr::Behaviour,
+}
+
+/// Builds the non-transport part of `MyBehaviour`, shared between the private
+/// (pnet) and public swarm-building paths below.
+fn build_behaviour(
+    key: &identity::Keypair,
+    relay_client: Option<relay::client::Behaviour>,
+) -> Result<MyBehaviour, Box<dyn Error + Send + Sync>> {
+    // To content-address message, we can take the hash of message and use it as an ID.
+    let message_id_fn = |message: &gossipsub::Message| {
+        let mut s = DefaultHasher::new();
+        message.data.hash(&mut s);
+        gossipsub::MessageId::from(s.finish().to_string())
+    };
+
+    // Set a custom gossipsub configuration
+    let gossipsub_config = gossipsub::ConfigBuilder::default()
+        // see https://docs.libp2p.io/concepts/pubsub/overview/#grafting-and-pruning
+        // how frequent to perform a check of grafting or pruning connections
+        //
+        // This is set a bit high to aid debugging by not cluttering the log space
+        .heartbeat_interval(Duration::from_secs(10))
+        // This sets the kind of message validation. The default is Strict (enforce message signing)
+        .validation_mode(gossipsub::ValidationMode::Strict)
+        // content-address messages. No two messages of the same content will be propagated.
+        .message_id_fn(message_id_fn)
+        .build()
+        .map_err(|msg| io::Error::new(io::ErrorKind::Other, msg))?; // Temporary hack because `build` does not return a proper `std::error::Error`.
+
+    // build a gossipsub network behaviour
+    let mut gossipsub = gossipsub::Behaviour::new(
+        gossipsub::MessageAuthenticity::Signed(key.clone()),
+        gossipsub_config,
+    )?;
+
+    // Score peers so spammy or invalid-message-sending peers get excluded from the
+    // mesh, denied gossip, or fully ignored before they can do real damage.
+    let (score_params, score_thresholds) = build_peer_score(&PeerScoreConfig::default());
+    gossipsub
+        .with_peer_score(score_params, score_thresholds)
+        .map_err(|msg| io::Error::new(io::ErrorKind::Other, msg))?;
+
+    let mdns = mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?;
+
+    // identify lets connected peers learn our listen addresses and protocol version,
+    // and is also how we learn our own observed address back from them
+    let identify =
+        identify::Behaviour::new(identify::Config::new("/chat/1.0.0".into(), key.public()));
+
+    // DCUtR needs our own peer id to coordinate the hole-punching handshake
+    let dcutr = dcutr::Behaviour::new(key.public().to_peer_id());
+
+    Ok(MyBehaviour {
+        gossipsub,
+        mdns,
+        relay_client,
+        identify,
+        dcutr,
+    })
 }
 
-pub fn build_swarm() -> Result<Swarm<MyBehaviour>, Box<dyn Error>> {
+/// Builds the swarm using the given pre-shared key decision. `psk` must come
+/// from a single call to [`load_pnet_psk`] shared with whatever other logic
+/// (e.g. `Backend::spawn`'s QUIC-listen and relay-dial gating) needs to agree
+/// with the transports registered here — calling `load_pnet_psk` again
+/// independently risks re-reading the env var/file and disagreeing with what
+/// this function actually wired up.
+pub fn build_swarm(psk: Option<PreSharedKey>) -> Result<Swarm<MyBehaviour>, Box<dyn Error>> {
     // Called also "switch", see documentation https://docs.libp2p.io/concepts/multiplex/switch
     // and also `libp2p::swarm` docs. The swarm contains the state of the network as a whole
     //
     // with_new_identity creates a new identity for the
     // local node generating a peer id
-    let swarm = libp2p::SwarmBuilder::with_new_identity()
-        // specifies the asynchronous runtime
-        .with_tokio()
-        // Next up we need to construct a transport. Each transport in libp2p provides encrypted streams.
-        // E.g. combining TCP to establish connections, TLS to encrypt these connections and Yamux
-        // to run one or more streams on a connection. Another libp2p transport is QUIC,
-        // providing encrypted streams out-of-the-box. We will stick to TCP for now.
-        // Each of these implement the Transport trait.
-        .with_tcp(
-            tcp::Config::default(),
-            tls::Config::new,
-            yamux::Config::default,
-        )?
-        // The .with_behaviour() method is used to specify the behavior of the nodes in the peer-to-peer network.
-        // In libp2p, a NetworkBehaviour defines how nodes react to events and communicate with each other.
-        // It's essentially the logic that governs the network interactions.
-        .with_behaviour(|key| {
-            // key is the cryptographic key-pair that identifies the node
-
-            // To content-address message, we can take the hash of message and use it as an ID.
-            let message_id_fn = |message: &gossipsub::Message| {
-                println!("inside message_id_fn, message = {:?}", message);
-                let mut s = DefaultHasher::new();
-                message.data.hash(&mut s);
-                gossipsub::MessageId::from(s.finish().to_string())
-            };
-
-            // Set a custom gossipsub configuration
-            let gossipsub_config = gossipsub::ConfigBuilder::default()
-                // see https://docs.libp2p.io/concepts/pubsub/overview/#grafting-and-pruning
-                // how frequent to perform a check of grafting or pruning connections
-                //
-                // This is set a bit high to aid debugging by not cluttering the log space
-                .heartbeat_interval(Duration::from_secs(10))
-                // This sets the kind of message validation. The default is Strict (enforce message signing)
-                .validation_mode(gossipsub::ValidationMode::Strict)
-                // content-address messages. No two messages of the same content will be propagated.
-                .message_id_fn(message_id_fn)
-                .build()
-                .map_err(|msg| io::Error::new(io::ErrorKind::Other, msg))?; // Temporary hack because `build` does not return a proper `std::error::Error`.
-
-            // build a gossipsub network behaviour
-            let gossipsub = gossipsub::Behaviour::new(
-                gossipsub::MessageAuthenticity::Signed(key.clone()),
-                gossipsub_config,
-            )?;
-
-            let mdns =
-                mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?;
-            Ok(MyBehaviour { gossipsub, mdns })
-        })?
-        .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
-        .build();
+    let swarm = if let Some(psk) = psk {
+        // Private network: QUIC and the relay client are both unauthenticated transports with
+        // no PSK check of their own, so admitting either here would let any peer bypass the
+        // pnet handshake and join the "private" mesh through them. Only the pnet-gated TCP
+        // transport is enabled in this mode.
+        libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_other_transport(move |key| build_tcp_transport(key, Some(psk)))?
+            .with_behaviour(|key| build_behaviour(key, None))?
+            .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+            .build()
+    } else {
+        libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            // Next up we need to construct a transport. Each transport in libp2p provides
+            // encrypted streams. E.g. combining TCP to establish connections, TLS to encrypt
+            // these connections and Yamux to run one or more streams on a connection. Each of
+            // these implement the Transport trait.
+            .with_other_transport(|key| build_tcp_transport(key, None))?
+            // QUIC provides encrypted streams out-of-the-box over UDP, which usually means
+            // faster connection setup and better behaviour through NATs than TCP. We add it
+            // as a second transport so the swarm dials/listens on whichever works, falling
+            // back to TCP when QUIC is unavailable.
+            .with_quic()
+            // The relay client transport lets us dial a relay, reserve a circuit on it, and
+            // have connections routed through that circuit until DCUtR manages to hole-punch
+            // a direct one.
+            .with_relay_client(tls::Config::new, yamux::Config::default)?
+            .with_behaviour(|key, relay_client| build_behaviour(key, Some(relay_client)))?
+            .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+            .build()
+    };
 
     Ok(swarm)
 }
@@ -1,11 +1,26 @@
-use chat_example::{build_swarm, MyBehaviourEvent};
-use futures::stream::StreamExt;
-use libp2p::{gossipsub, mdns, swarm::SwarmEvent};
+use chat_example::backend::{Backend, Command, Event};
+use chat_example::cache::MessageCache;
+use chat_example::CHAT_TOPIC;
+use libp2p::gossipsub;
 use std::error::Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::io::AsyncBufReadExt;
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::EnvFilter;
 
+/// Environment variable pointing at the SQLite file backing the message cache.
+const CACHE_PATH_ENV: &str = "CHAT_CACHE_PATH";
+const DEFAULT_CACHE_PATH: &str = "chat-cache.sqlite3";
+
+/// On startup, replay cached messages on our topic received in this recent a window, so a
+/// restarted node shows again what it received before it went down. Only messages this node
+/// itself received are in the cache; nothing gossiped while it was offline is fetched.
+const REPLAY_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Prefix that turns a line of stdin into a JSONPath query against the cache instead of a
+/// chat message to publish, e.g. `/query $.type`.
+const QUERY_PREFIX: &str = "/query ";
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     // it is an utility the for implementing and composing tracing subscribers
@@ -14,65 +29,81 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .with_env_filter(EnvFilter::from_default_env().add_directive(LevelFilter::INFO.into()))
         .try_init();
 
-    // build the swarm
-    let mut swarm = build_swarm()?;
+    // build the swarm and hand it off to the backend task
+    let mut backend = Backend::spawn()?;
 
-    // Create a Gossipsub topic
-    let topic = gossipsub::IdentTopic::new("test-topic");
-    // subscribes to our topic
-    swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+    // open the persistent message cache so reconnecting peers can replay what they missed
+    let cache_path =
+        std::env::var(CACHE_PATH_ENV).unwrap_or_else(|_| DEFAULT_CACHE_PATH.to_string());
+    let cache = MessageCache::open(&cache_path).await?;
+
+    // Create a Gossipsub topic and subscribe to it
+    let topic = gossipsub::IdentTopic::new(CHAT_TOPIC);
+    backend.command(Command::Subscribe(topic.clone())).await?;
+
+    // Replay whatever we cached for this topic in the replay window, so messages received
+    // before a restart are shown again.
+    let replay_since =
+        SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64 - REPLAY_WINDOW.as_secs() as i64;
+    for cached in cache.get_since(&topic.hash(), replay_since).await? {
+        tracing::info!(
+            "Replaying cached message: '{}' with id: {}",
+            String::from_utf8_lossy(&cached.data),
+            cached.message_id,
+        );
+    }
 
     // Read full lines from stdin
     let mut stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
 
-    // Listen on all interfaces and whatever port the OS assigns
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
-
     println!("Enter messages via STDIN and they will be sent to connected peers using Gossipsub");
+    println!("Enter \"{QUERY_PREFIX}<jsonpath>\" to search the cache instead, e.g. \"{QUERY_PREFIX}$.type\"");
 
     // Kick it off
     loop {
         // Look at the docs of the `select` macro
         tokio::select! {
             Ok(Some(line)) = stdin.next_line() => {
-                // if there is some new input from stdin publish to everyone
-                // subscribed to the topic we created. This will call the
-                // `message_id_fn` to create an id for our message.
-                if let Err(e) = swarm
-                    .behaviour_mut().gossipsub
-                    .publish(topic.clone(), line.as_bytes()) {
-                    tracing::info!("Publish error: {e:?}");
+                if let Some(jsonpath) = line.strip_prefix(QUERY_PREFIX) {
+                    // search the cache instead of publishing
+                    match cache.query(&topic.hash(), jsonpath).await {
+                        Ok(matches) => {
+                            println!("{} cached message(s) match {jsonpath}:", matches.len());
+                            for cached in matches {
+                                println!("  [{}] {}", cached.message_id, String::from_utf8_lossy(&cached.data));
+                            }
+                        }
+                        Err(e) => tracing::warn!("Query error: {e}"),
+                    }
+                } else {
+                    // if there is some new input from stdin publish to everyone
+                    // subscribed to the topic we created.
+                    backend
+                        .command(Command::Publish { topic: topic.clone(), data: line.into_bytes() })
+                        .await?;
                 }
             }
-            event = swarm.select_next_some() => match event {
-                SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
-                    for (peer_id, _multiaddr) in list {
-                        tracing::info!("mDNS discovered a new peer: {peer_id}");
-                        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+            Some(event) = backend.next_event() => match event {
+                Event::MessageReceived { topic, id, source, data } => {
+                    let received_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+                    if let Err(e) = cache.store(&topic, &id, &data, received_at).await {
+                        tracing::warn!("Failed to cache message {id}: {e}");
                     }
+                    tracing::info!(
+                        "Got message: '{}' with id: {id} from peer: {source}",
+                        String::from_utf8_lossy(&data),
+                    );
                 },
-                SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Expired(list))) => {
-                    for (peer_id, _multiaddr) in list {
-                        tracing::info!("mDNS discover peer has expired: {peer_id}");
-                        swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
-                    }
+                Event::PeerDiscovered(peer_id) => {
+                    tracing::info!("mDNS discovered a new peer: {peer_id}");
                 },
-                SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message {
-                    propagation_source: peer_id,
-                    message_id: id,
-                    message,
-                })) => tracing::info!(
-                        "Got message: '{}' with id: {id} from peer: {peer_id}",
-                        String::from_utf8_lossy(&message.data),
-                    ),
-                SwarmEvent::NewListenAddr { address, .. } => {
-                    tracing::info!("Local node is listening on {address}");
+                Event::PeerExpired(peer_id) => {
+                    tracing::info!("mDNS discover peer has expired: {peer_id}");
                 },
                 // Adding a callback on a custom event is easy! Look
-                SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic})) => {
+                Event::Subscribed { peer_id, topic } => {
                     tracing::info!("PeerId {peer_id} subscribed to topic {topic}");
                 },
-                _ => {}
             }
         }
     }